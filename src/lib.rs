@@ -24,8 +24,8 @@ extern "C" {
 use failure::*;
 use num_traits::identities::Zero;
 use std::collections::vec_deque::VecDeque;
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::hash::Hash;
 
 pub struct ProjectArgs {
     // TODO: make Vec
@@ -36,6 +36,11 @@ pub struct ProjectArgs {
     ira_present_value: u32,
     ira_effective_annual_rate: f64,
     basis_value: u32,
+    // Rollovers offered to `successors` are `0, rollover_increment, 2 *
+    // rollover_increment, ...`, up to the largest multiple of `rollover_increment` that does not
+    // exceed `max_rollover`.
+    rollover_increment: u32,
+    max_rollover: u32,
     birth_year: u16,
     birth_month: u8,
     start_year: u16,
@@ -63,6 +68,8 @@ impl ProjectArgs {
             err_msg("IRA value must be greater than the basis")
         } else if self.ira_effective_annual_rate > 1.0 || self.ira_effective_annual_rate < 0.0 {
             err_msg("IRA rate must be between 0 and 1")
+        } else if self.rollover_increment == 0 {
+            err_msg("Rollover increment must be > 0")
         } else if self.birth_year > self.start_year {
             err_msg("Birth year must be <= start year")
         } else if self.start_year > self.end_year {
@@ -83,7 +90,7 @@ enum Action {
     RolloverThenContinue(u32),
 }
 
-type Cost = u32;
+type Cost = i64;
 
 #[derive(Clone, Debug, Hash, Eq, Ord, PartialEq, PartialOrd, new)]
 pub struct State {
@@ -99,6 +106,34 @@ pub struct State {
     total_tax: u32,
 }
 
+// Key used to memoize cost-to-go during the search below. `previous_action`, `total_cash`, and
+// `total_tax` are excluded because cost-to-go only depends on `(year, roth, ira, basis)`
+// (`total_tax` is never read by `take_action`/`maximum_after_tax_cash`, and `total_cash` cancels
+// out of the `diff` computed in `take_action`). `roth`/`ira`/`basis` are additionally bucketed to
+// `rollover_increment`, the grid resolution the caller already chose for rollovers, so that
+// distinct rollover histories which land within one grid step of each other collapse onto the
+// same entry instead of each requiring its own. This trades away a sliver of precision (no finer
+// than `successors` already distinguishes between) for a state space that stays polynomial in the
+// number of years instead of exponential in the number of decisions.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+struct StateKey {
+    year: u16,
+    roth: u32,
+    ira: u32,
+    basis: u32,
+}
+
+impl StateKey {
+    fn new(state: &State, bucket_width: u32) -> Self {
+        StateKey {
+            year: state.year,
+            roth: state.roth / bucket_width,
+            ira: state.ira / bucket_width,
+            basis: state.basis / bucket_width,
+        }
+    }
+}
+
 impl State {
     // Assuming ira is withdrawn immediately. TODO: use max(withdrawn year-end, year-begin)?
     /// Returns the after-tax value of all accounts when liquidated this year
@@ -165,102 +200,110 @@ impl State {
         };
 
         // TODO: Store in state to cache calculation
-        let diff = new_state.maximum_after_tax_cash(income) - self.maximum_after_tax_cash(income);
+        // A larger rollover can push this year's bracket stacking far enough that liquidating
+        // now is actually worse than last year, so this is signed rather than relying on it
+        // always being a gain.
+        let diff = new_state.maximum_after_tax_cash(income) as Cost
+            - self.maximum_after_tax_cash(income) as Cost;
         Some((new_state, diff))
     }
 }
 
 fn successors(parent: &State, args: &ProjectArgs) -> impl IntoIterator<Item = (State, Cost)> {
-    vec![
-        parent.take_action(
-            Action::Continue,
-            args.birth_year,
-            args.birth_month,
-            args.yearly_taxable_income_excluding_ira,
-            args.roth_effective_annual_rate,
-            args.ira_effective_annual_rate,
-            args.inflation_effective_annual_rate,
-        ),
-        parent.take_action(
-            Action::RolloverThenContinue(1000),
-            args.birth_year,
-            args.birth_month,
-            args.yearly_taxable_income_excluding_ira,
-            args.roth_effective_annual_rate,
-            args.ira_effective_annual_rate,
-            args.inflation_effective_annual_rate,
-        ),
-    ]
-    .into_iter()
-    .filter_map(|x| x)
+    let mut actions = vec![Action::Continue];
+    actions.extend(
+        (1..=args.max_rollover / args.rollover_increment)
+            .map(|k| Action::RolloverThenContinue(k * args.rollover_increment)),
+    );
+
+    actions
+        .into_iter()
+        .filter_map(|action| {
+            parent.take_action(
+                action,
+                args.birth_year,
+                args.birth_month,
+                args.yearly_taxable_income_excluding_ira,
+                args.roth_effective_annual_rate,
+                args.ira_effective_annual_rate,
+                args.inflation_effective_annual_rate,
+            )
+        })
+        .collect::<Vec<_>>()
 }
 
-// TODO: parallelize?
-pub fn shortest_path_recursive<N, C, FN, IN, FS>(
-    current: N,
-    current_cost: C,
-    shortest_path: &mut Option<(VecDeque<N>, C)>,
-    successors: &FN,
-    success: &FS,
-) -> bool
-where
-    N: Eq + Hash + Clone,
-    C: Zero + Ord + Copy,
-    FN: Fn(&N) -> IN,
-    IN: IntoIterator<Item = (N, C)>,
-    FS: Fn(&N) -> bool,
-{
-    let mut found_current_shortest_path = false;
-
-    if success(&current) {
-        // TODO: cleanup
-        // if let chain isn't yet stable
-        let path = shortest_path.get_or_insert_with(|| (VecDeque::new(), current_cost));
-        if current_cost > path.1 || path.0.len() == 0 {
-            found_current_shortest_path = true;
-            path.1 = current_cost;
-            path.0.clear();
-        }
-    } else {
-        for (next, cost) in successors(&current) {
-            found_current_shortest_path = shortest_path_recursive(
-                next,
-                current_cost + cost,
-                shortest_path,
-                successors,
-                success,
-            ) || found_current_shortest_path;
-        }
+// Computes the best cost-to-go from `state` to a year past `args.end_year`, memoized on
+// `StateKey` so that states reached via different rollover histories, but bucketed to the same
+// key, are only ever explored once. Assumes `successors` generates a topologically ordered DAG
+// (here, `year` strictly advances at each step), so this never revisits a key while it's still
+// being computed. Stores the chosen `Action` rather than the resulting successor `State`, since
+// the successor reached first for a given key may come from a different (bucket-equivalent)
+// lineage than whichever state later reuses the cache entry; `shortest_path` below replays the
+// action from the real predecessor instead of reusing a captured successor.
+fn best_cost_to_go(
+    state: &State,
+    args: &ProjectArgs,
+    memo: &mut HashMap<StateKey, (Cost, Option<Action>)>,
+) -> Option<(Cost, Option<Action>)> {
+    let key = StateKey::new(state, args.rollover_increment);
+    if let Some(cached) = memo.get(&key) {
+        return Some(cached.clone());
     }
 
-    if found_current_shortest_path {
-        if let Some(path) = shortest_path {
-            path.0.push_front(current);
-        }
+    let result = if state.year > args.end_year {
+        Some((Cost::zero(), None))
+    } else {
+        successors(state, args)
+            .into_iter()
+            .filter_map(|(next, edge_cost)| {
+                let (remaining_cost, _) = best_cost_to_go(&next, args, memo)?;
+                Some((edge_cost + remaining_cost, next.previous_action))
+            })
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+    };
+
+    if let Some(result) = &result {
+        memo.insert(key, result.clone());
     }
 
-    found_current_shortest_path
+    result
 }
 
-// TODO: Docs
-// Returns the lowest-cost terminating path, if the generated graph is a topologically ordered DAG.
-// The assumptions here are not checked. TODO: panic if assumptions broken?
-// All nodes in the graph will be visited.
-pub fn shortest_path<N, C, FN, IN, FS>(
-    start: N,
-    successors: &FN,
-    success: &FS,
-) -> Option<(VecDeque<N>, C)>
-where
-    N: Eq + Hash + Clone,
-    C: Zero + Ord + Copy,
-    FN: Fn(&N) -> IN,
-    IN: IntoIterator<Item = (N, C)>,
-    FS: Fn(&N) -> bool,
-{
-    let mut shortest_path: Option<(VecDeque<N>, C)> = None;
-    shortest_path_recursive(start, C::zero(), &mut shortest_path, successors, success);
-    shortest_path
+// TODO: parallelize?
+// Returns the highest-cost terminating path through the states reachable from `start`, bucketing
+// `roth`/`ira`/`basis` to `args.rollover_increment` so the memoized search in `best_cost_to_go`
+// stays polynomial in `args.end_year - args.start_year` rather than exponential in the number of
+// rollover decisions.
+fn shortest_path(start: State, args: &ProjectArgs) -> Option<(VecDeque<State>, Cost)> {
+    let mut memo = HashMap::new();
+    let (total_cost, _) = best_cost_to_go(&start, args, &mut memo)?;
+
+    let mut path = VecDeque::new();
+    let mut current = start;
+    loop {
+        let key = StateKey::new(&current, args.rollover_increment);
+        let (_, action) = memo.get(&key)?.clone();
+        let is_terminal = current.year > args.end_year;
+        path.push_back(current.clone());
+
+        match action {
+            Some(action) if !is_terminal => {
+                let (next, _) = current.take_action(
+                    action,
+                    args.birth_year,
+                    args.birth_month,
+                    args.yearly_taxable_income_excluding_ira,
+                    args.roth_effective_annual_rate,
+                    args.ira_effective_annual_rate,
+                    args.inflation_effective_annual_rate,
+                )?;
+                current = next;
+            }
+            _ => break,
+        }
+    }
+
+    Some((path, total_cost))
 }
 
 // TODO: #[wasm_bindgen]
@@ -277,11 +320,7 @@ pub fn project(args: &ProjectArgs) -> Option<(VecDeque<State>, Cost)> {
         args.starting_cash,
     );
 
-    dbg!(shortest_path(
-        start,
-        &mut |s| successors(s, args),
-        &mut |s| s.year > args.end_year,
-    ))
+    dbg!(shortest_path(start, args))
 }
 
 // TODO: only applies if (spouse not sole beneficiary) || (their age >= your age - 10)
@@ -414,6 +453,106 @@ mod tests {
         assert_eq!(0, get_tax(0));
     }
 
+    #[test]
+    fn validate_rejects_zero_rollover_increment() {
+        let args = ProjectArgs {
+            yearly_taxable_income_excluding_ira: 0,
+            inflation_effective_annual_rate: 0.0,
+            roth_present_value: 0,
+            roth_effective_annual_rate: 0.0,
+            ira_present_value: 100_000,
+            ira_effective_annual_rate: 0.0,
+            basis_value: 0,
+            rollover_increment: 0,
+            max_rollover: 2_000,
+            birth_year: 2000,
+            birth_month: 1,
+            start_year: 2026,
+            end_year: 2026,
+            starting_cash: 0,
+        };
+
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn successors_enumerates_feasible_rollover_grid() {
+        let args = ProjectArgs {
+            yearly_taxable_income_excluding_ira: 0,
+            inflation_effective_annual_rate: 0.0,
+            roth_present_value: 0,
+            roth_effective_annual_rate: 0.0,
+            ira_present_value: 100_000,
+            ira_effective_annual_rate: 0.0,
+            basis_value: 0,
+            rollover_increment: 1_000,
+            max_rollover: 3_000,
+            birth_year: 1990,
+            birth_month: 1,
+            start_year: 2020,
+            end_year: 2020,
+            starting_cash: 0,
+        };
+
+        // ira (2_500) can absorb rollovers of 1_000 or 2_000, but not 3_000.
+        let state = State::new(2020, 0, 2_500, 0, 0);
+
+        let mut actions: Vec<Action> = successors(&state, &args)
+            .into_iter()
+            .map(|(next, _)| next.previous_action.unwrap())
+            .collect();
+        actions.sort();
+
+        assert_eq!(
+            vec![
+                Action::Continue,
+                Action::RolloverThenContinue(1_000),
+                Action::RolloverThenContinue(2_000),
+            ],
+            actions
+        );
+    }
+
+    #[test]
+    fn shortest_path_prefers_the_rollover_with_higher_cost_to_go() {
+        let args = ProjectArgs {
+            yearly_taxable_income_excluding_ira: 50_000,
+            inflation_effective_annual_rate: 0.0,
+            roth_present_value: 0,
+            roth_effective_annual_rate: 0.0,
+            ira_present_value: 100_000,
+            ira_effective_annual_rate: 0.0,
+            basis_value: 0,
+            rollover_increment: 2_000,
+            max_rollover: 2_000,
+            birth_year: 2000,
+            birth_month: 1,
+            start_year: 2026,
+            end_year: 2026,
+            starting_cash: 0,
+        };
+
+        let start = State::new(
+            args.start_year,
+            args.roth_present_value,
+            args.ira_present_value,
+            args.basis_value,
+            args.starting_cash,
+        );
+
+        let (path, cost) = shortest_path(start, &args).unwrap();
+
+        assert_eq!(43_182, cost);
+        assert_eq!(2, path.len());
+        assert_eq!(
+            Some(Action::RolloverThenContinue(2_000)),
+            path[1].previous_action.clone()
+        );
+    }
+
+    // TODO: un-ignore once this has actually been benchmarked; the bucketed memo in
+    // `shortest_path` should keep this polynomial rather than exponential in the number of
+    // years, but that hasn't been measured here.
     #[bench]
     #[ignore]
     fn long_project(b: &mut Bencher) {
@@ -425,6 +564,8 @@ mod tests {
             ira_present_value: 6000,
             ira_effective_annual_rate: 0.08,
             basis_value: 0,
+            rollover_increment: 1000,
+            max_rollover: 5000,
             birth_year: 1955,
             birth_month: 6,
             start_year: 2019,
@@ -445,6 +586,8 @@ mod tests {
             ira_present_value: 6000,
             ira_effective_annual_rate: 0.08,
             basis_value: 0,
+            rollover_increment: 1000,
+            max_rollover: 5000,
             birth_year: 1955,
             birth_month: 6,
             start_year: 2035,